@@ -2,30 +2,86 @@ use harfbuzz_wasm::{debug, Font, Glyph, GlyphBuffer};
 use wasm_bindgen::prelude::*;
 use std::collections::HashMap;
 
-use t5_quantized::{ModelConditionalGeneration, ConditionalGenerationParams};
+use t5_quantized::{ModelConditionalGeneration, ConditionalGenerationParams, TextTransform};
 
 static WEIGHTS: &[u8; 64350016] = include_bytes!("../model.gguf");
 static TOKENIZER: &[u8; 1389353] = include_bytes!("../tokenizer.json");
 static CONFIG: &[u8; 1206] = include_bytes!("../config.json");
 
-static mut MODEL: Option<ModelConditionalGeneration> = None;
+// `MODEL` is boxed behind `TextTransform` rather than naming
+// `ModelConditionalGeneration` directly so swapping in a different
+// embedded model (e.g. a decoder-only quantized LLM) only requires
+// changing the embedded files above and the `load` call in `init_model`.
+static mut MODEL: Option<Box<dyn TextTransform>> = None;
 
 static mut GENERATE_CACHE: Option<HashMap<String, String>> = None;
 
-fn init_model() -> Result<ModelConditionalGeneration, String> {
-    let model = ModelConditionalGeneration::load(WEIGHTS.to_vec(), TOKENIZER.to_vec(), CONFIG.to_vec());
-    model
+fn init_model() -> Result<Box<dyn TextTransform>, String> {
+    let model = ModelConditionalGeneration::load(WEIGHTS.to_vec(), TOKENIZER.to_vec(), CONFIG.to_vec())?;
+    Ok(Box::new(model))
 }
 
-fn build_gen_params(prompt: &str) -> ConditionalGenerationParams {
+/// Mirrors the layout of HarfBuzz's `hb_feature_t`: a four-byte tag, the
+/// feature's value, and the cluster range it applies to. The wasm shaper
+/// host writes an array of these directly into the module's own linear
+/// memory before calling `shape`, so `features` is a raw pointer rather
+/// than an opaque ref like `font_ref`/`buf_ref`.
+#[repr(C)]
+struct HbFeature {
+    tag: u32,
+    value: u32,
+    start: u32,
+    end: u32,
+}
+
+/// Feature tags this font recognizes as task selectors. The first enabled
+/// feature of the run whose tag is in this table selects the task; the
+/// order of the table itself does not matter. Unlike registered OpenType
+/// features these are private-use tags chosen for this font; any tag not
+/// in the table falls back to the default translation task. Following the usual HarfBuzz convention for
+/// boolean features, a tag only selects its task when `value != 0` — a run
+/// tagged e.g. `xde=0` explicitly turns the feature off rather than
+/// selecting it, matching how a client disables a feature it doesn't want.
+const TASK_FEATURES: &[(&[u8; 4], &str)] = &[
+    (b"xde ", "translate English to German:"),
+    (b"xfr ", "translate English to French:"),
+    (b"xro ", "translate English to Romanian:"),
+    (b"xsum", "summarize:"),
+];
+
+const DEFAULT_TASK_PREFIX: &str = "translate English to German:";
+
+fn task_prefix_from_features(features: u32, num_features: u32) -> &'static str {
+    if features == 0 || num_features == 0 {
+        return DEFAULT_TASK_PREFIX;
+    }
+    let features =
+        unsafe { std::slice::from_raw_parts(features as *const HbFeature, num_features as usize) };
+    for feature in features {
+        if feature.value == 0 {
+            continue;
+        }
+        let tag = feature.tag.to_be_bytes();
+        if let Some((_, prefix)) = TASK_FEATURES.iter().find(|(t, _)| **t == tag) {
+            return prefix;
+        }
+    }
+    DEFAULT_TASK_PREFIX
+}
+
+fn build_gen_params() -> ConditionalGenerationParams {
     ConditionalGenerationParams {
-        prompt: prompt.to_string(),
         temperature: 0.0,
         seed: 0,
         top_p: 1.0,
         repeat_penalty: 1.1,
         repeat_last_n: 1,
-        max_length: Some(512),
+        max_length: None,
+        min_length: 0,
+        num_beams: 1,
+        length_penalty: 1.0,
+        early_stopping: true,
+        no_repeat_ngram_size: 3,
     }
 }
 
@@ -49,11 +105,12 @@ pub fn shape(
     _shape_plan: u32,
     font_ref: u32,
     buf_ref: u32,
-    _features: u32,
-    _num_features: u32,
+    features: u32,
+    num_features: u32,
 ) -> i32 {
     let font = Font::from_ref(font_ref);
     let mut buffer = GlyphBuffer::from_ref(buf_ref);
+    let task_prefix = task_prefix_from_features(features, num_features);
 
     // Get buffer as string
     let buf_u8: Vec<u8> = buffer.glyphs.iter().map(|g| g.codepoint as u8).collect();
@@ -99,7 +156,7 @@ pub fn shape(
         }
 
         // Get model
-        let model: &mut ModelConditionalGeneration = unsafe { MODEL.as_mut().unwrap() };
+        let model: &mut Box<dyn TextTransform> = unsafe { MODEL.as_mut().unwrap() };
         let cache: &mut HashMap<String, String> = unsafe { GENERATE_CACHE.as_mut().unwrap() };
 
         let punctuation = vec!['.', '!', '?'];
@@ -126,18 +183,19 @@ pub fn shape(
                 continue;
             }
             let output_str = if !sentences.peek().is_none() || punctuation.contains(&sentence.chars().last().unwrap()) {
-                if cache.contains_key(sentence) {
+                let cache_key = format!("{}\u{0}{}", task_prefix, sentence);
+                if cache.contains_key(&cache_key) {
                     debug(&format!("Cache hit: {}", sentence));
-                    cache.get(sentence).unwrap().to_string()
+                    cache.get(&cache_key).unwrap().to_string()
                 } else {
-                    let prompt = format!("translate English to German:{}", sentence);
-                    let gen_params = build_gen_params(&prompt);
-                    let output = model.decode(gen_params);
+                    let prompt = format!("{}{}", task_prefix, sentence);
+                    let gen_params = build_gen_params();
+                    let output = model.run(&prompt, gen_params);
                     match output {
-                        Ok(output) => {
-                            debug(&format!("Generation: {}", output.generation));
-                            cache.insert(sentence.to_string(), output.generation.to_string());
-                            output.generation
+                        Ok(generation) => {
+                            debug(&format!("Generation: {}", generation));
+                            cache.insert(cache_key, generation.clone());
+                            generation
                         }
                         Err(e) => {
                             debug(&format!("Error decoding: {}", e));
@@ -0,0 +1,211 @@
+use std::io::Cursor;
+
+use candle_core::quantized::gguf_file;
+use candle_core::{Device, Tensor};
+use candle_transformers::generation::LogitsProcessor;
+use candle_transformers::models::quantized_llama::ModelWeights;
+use serde::Deserialize;
+use tokenizers::Tokenizer;
+
+use crate::{banned_ngram_tokens, ConditionalGenerationParams, TextTransform, TokenOutputStream};
+
+/// A decoder-only quantized model in candle's quantized-llama format
+/// (`quantized_llama::ModelWeights`), embedded the same way the T5 path
+/// embeds its GGUF weights. Its GGUF metadata must carry
+/// `llama.context_length`; other architectures such as phi are not loaded. Unlike T5
+/// there is no encoder/decoder split: the prompt and the generated
+/// continuation share one autoregressive token stream, so `run` just keeps
+/// extending `tokens` and feeding the model's own KV cache one token at a
+/// time.
+pub struct QuantizedCausalModel {
+    model: ModelWeights,
+    tokenizer: Tokenizer,
+    eos_token_id: u32,
+    context_length: usize,
+}
+
+#[derive(Deserialize)]
+struct CausalGenerationConfig {
+    eos_token_id: u32,
+}
+
+impl TextTransform for QuantizedCausalModel {
+    fn load(weights: Vec<u8>, tokenizer: Vec<u8>, config: Vec<u8>) -> Result<Self, String> {
+        let device = &Device::Cpu;
+        let mut reader = Cursor::new(weights);
+        let content = gguf_file::Content::read(&mut reader)
+            .map_err(|e| format!("Failed to read gguf: {:?}", e.to_string()))?;
+        // Unlike the T5 path, this llama.cpp-family loader derives its
+        // architecture hyperparameters (layer count, head count, context
+        // length, ...) from the GGUF file's own metadata rather than a
+        // separate config; `config` here only needs to carry the one thing
+        // that isn't in the GGUF metadata under a key we read: which token
+        // id ends generation.
+        let context_length = content
+            .metadata
+            .get("llama.context_length")
+            .and_then(|v| v.to_u32().ok())
+            .ok_or_else(|| "Missing llama.context_length in gguf metadata".to_string())?
+            as usize;
+        let model = ModelWeights::from_gguf(content, &mut reader, device)
+            .map_err(|e| format!("Failed to load model: {:?}", e.to_string()))?;
+        let gen_config: CausalGenerationConfig = serde_json::from_slice(&config)
+            .map_err(|e| format!("Failed to parse config: {:?}", e.to_string()))?;
+        let tokenizer = Tokenizer::from_bytes(&tokenizer).map_err(|m| m.to_string())?;
+        Ok(Self {
+            model,
+            tokenizer,
+            eos_token_id: gen_config.eos_token_id,
+            context_length,
+        })
+    }
+
+    fn run(&mut self, prompt: &str, params: ConditionalGenerationParams) -> Result<String, String> {
+        if params.num_beams > 1 {
+            return Err("beam search is not yet supported for causal models".to_string());
+        }
+
+        let device = &Device::Cpu;
+        self.model.clear_kv_cache();
+
+        let temperature = if params.temperature <= 0. {
+            None
+        } else {
+            Some(params.temperature)
+        };
+        let top_p = if params.top_p <= 0. || params.top_p >= 1. {
+            None
+        } else {
+            Some(params.top_p)
+        };
+        let mut logits_processor = LogitsProcessor::new(params.seed, temperature, top_p);
+
+        let prompt_tokens = self
+            .tokenizer
+            .encode(prompt, true)
+            .map_err(|m| m.to_string())?
+            .get_ids()
+            .to_vec();
+        let prompt_len = prompt_tokens.len();
+        // Unlike T5's relative-position attention, this decoder-only family
+        // has a real fixed context window baked into its GGUF metadata, so
+        // unlike the T5 path's safety-ceiling fallback, derive the cap from
+        // the loaded model itself.
+        let max_length = params
+            .max_length
+            .unwrap_or(self.context_length)
+            .min(self.context_length)
+            .max(prompt_len + 1);
+
+        let mut tokens = prompt_tokens;
+        let mut token_stream = TokenOutputStream::new();
+        let mut decoded = String::new();
+        let mut index_pos = 0;
+        let mut next_token = None;
+
+        loop {
+            if tokens.len() >= max_length {
+                break;
+            }
+            let input_ids: Vec<u32> = match next_token {
+                Some(token) => vec![token],
+                None => tokens.clone(),
+            };
+            let tsr = match Tensor::new(input_ids.as_slice(), device) {
+                Ok(tsr) => tsr,
+                Err(e) => {
+                    return Err(format!("Failed to create tensor: {:?}", e.to_string()));
+                }
+            };
+            let input_ids = match tsr.unsqueeze(0) {
+                Ok(tsr) => tsr,
+                Err(e) => {
+                    return Err(format!("Failed to unsqueeze tensor: {:?}", e.to_string()));
+                }
+            };
+            let logits = match self.model.forward(&input_ids, index_pos) {
+                Ok(tsr) => tsr,
+                Err(e) => {
+                    return Err(format!("Failed to run model: {:?}", e.to_string()));
+                }
+            };
+            let logits = match logits.squeeze(0) {
+                Ok(tsr) => tsr,
+                Err(e) => {
+                    return Err(format!("Failed to squeeze tensor: {:?}", e.to_string()));
+                }
+            };
+            index_pos += input_ids.dims()[1];
+
+            let generated: Vec<u32> = tokens[prompt_len..].to_vec();
+            let logits = if params.repeat_penalty == 1. {
+                logits
+            } else {
+                let start_at = generated.len().saturating_sub(params.repeat_last_n);
+                candle_transformers::utils::apply_repeat_penalty(
+                    &logits,
+                    params.repeat_penalty,
+                    &generated[start_at..],
+                )
+                .map_err(|e| format!("Failed to apply repeat penalty: {:?}", e.to_string()))?
+            };
+            let logits = if params.no_repeat_ngram_size == 0 {
+                logits
+            } else {
+                let banned = banned_ngram_tokens(&generated, params.no_repeat_ngram_size);
+                if banned.is_empty() {
+                    logits
+                } else {
+                    let mut values = match logits.to_vec1::<f32>() {
+                        Ok(v) => v,
+                        Err(e) => {
+                            return Err(format!("Failed to read logits: {:?}", e.to_string()));
+                        }
+                    };
+                    for token_id in banned {
+                        values[token_id as usize] = f32::NEG_INFINITY;
+                    }
+                    match Tensor::new(values.as_slice(), device) {
+                        Ok(tsr) => tsr,
+                        Err(e) => {
+                            return Err(format!("Failed to create tensor: {:?}", e.to_string()));
+                        }
+                    }
+                }
+            };
+            let logits = if generated.len() < params.min_length {
+                let mut values = match logits.to_vec1::<f32>() {
+                    Ok(v) => v,
+                    Err(e) => {
+                        return Err(format!("Failed to read logits: {:?}", e.to_string()));
+                    }
+                };
+                values[self.eos_token_id as usize] = f32::NEG_INFINITY;
+                match Tensor::new(values.as_slice(), device) {
+                    Ok(tsr) => tsr,
+                    Err(e) => {
+                        return Err(format!("Failed to create tensor: {:?}", e.to_string()));
+                    }
+                }
+            } else {
+                logits
+            };
+
+            let next_token_id = match logits_processor.sample(&logits) {
+                Ok(tsr) => tsr,
+                Err(e) => {
+                    return Err(format!("Failed to sample tensor: {:?}", e.to_string()));
+                }
+            };
+            if next_token_id == self.eos_token_id {
+                break;
+            }
+            tokens.push(next_token_id);
+            next_token = Some(next_token_id);
+            if let Some(text) = token_stream.next_token(&self.tokenizer, next_token_id)? {
+                decoded += &text;
+            }
+        }
+        Ok(decoded)
+    }
+}
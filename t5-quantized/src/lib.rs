@@ -5,12 +5,138 @@ pub use candle_transformers::models::quantized_t5::{
 };
 use tokenizers::Tokenizer;
 
+pub mod causal;
+
+/// A text generation model that can be embedded in a font: load it once
+/// from its GGUF weights, tokenizer and config, then run it against a
+/// prompt with a set of generation params. `shape` dispatches through
+/// `Box<dyn TextTransform>` so the wasm module isn't statically wired to
+/// any one model family (T5, a quantized decoder-only LLM, ...).
+pub trait TextTransform {
+    fn load(weights: Vec<u8>, tokenizer: Vec<u8>, config: Vec<u8>) -> Result<Self, String>
+    where
+        Self: Sized;
+
+    fn run(&mut self, prompt: &str, params: ConditionalGenerationParams) -> Result<String, String>;
+}
+
 pub struct ModelConditionalGeneration {
     model: T5ForConditionalGeneration,
     tokenizer: Tokenizer,
     config: Config,
 }
 
+/// Incrementally decodes a stream of token ids into the text those tokens
+/// add to the output, one token at a time.
+///
+/// SentencePiece byte-fallback tokenizers (e.g. `<0xC3>`, `<0xA4>`) can
+/// represent a single Unicode code point as several tokens, so decoding a
+/// token in isolation can split a multi-byte character in two. Re-decoding
+/// the growing token list on every step and diffing against the previous
+/// decode lets the tokenizer's own merging rules assemble such code points
+/// before they are emitted.
+pub(crate) struct TokenOutputStream {
+    tokens: Vec<u32>,
+    prev_index: usize,
+    current_index: usize,
+}
+
+impl TokenOutputStream {
+    pub(crate) fn new() -> Self {
+        Self {
+            tokens: Vec::new(),
+            prev_index: 0,
+            current_index: 0,
+        }
+    }
+
+    /// Pushes `token`, decodes the tokens seen so far, and returns the text
+    /// that the new token contributed, if any. Returns `Ok(None)` while the
+    /// latest token still leaves an incomplete UTF-8 sequence pending.
+    pub(crate) fn next_token(&mut self, tokenizer: &Tokenizer, token: u32) -> Result<Option<String>, String> {
+        self.tokens.push(token);
+        let prev_text = if self.tokens.is_empty() {
+            String::new()
+        } else {
+            let prev_tokens = &self.tokens[self.prev_index..self.current_index];
+            tokenizer
+                .decode(prev_tokens, true)
+                .map_err(|m| m.to_string())?
+        };
+        let text = tokenizer
+            .decode(&self.tokens[self.prev_index..], true)
+            .map_err(|m| m.to_string())?;
+        if text.len() > prev_text.len() && !text.ends_with('\u{fffd}') {
+            let text = text.split_at(prev_text.len()).1.to_string();
+            self.prev_index = self.current_index;
+            self.current_index = self.tokens.len();
+            Ok(Some(text))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+/// A single beam-search hypothesis: the token ids generated so far and
+/// their cumulative (unnormalized) log-probability.
+struct BeamHypothesis {
+    tokens: Vec<u32>,
+    cumulative_logprob: f64,
+}
+
+/// Returns the tokens that would complete an n-gram already present in
+/// `token_ids`, given the n-1 tokens currently trailing the sequence. The
+/// caller sets the logits of these tokens to `NEG_INFINITY` so the next
+/// sampled token cannot reproduce an n-gram that has already been
+/// generated.
+pub(crate) fn banned_ngram_tokens(token_ids: &[u32], n: usize) -> Vec<u32> {
+    if n == 0 || token_ids.len() < n {
+        return vec![];
+    }
+    let trailing = &token_ids[token_ids.len() - (n - 1)..];
+    token_ids
+        .windows(n)
+        .filter(|window| &window[..n - 1] == trailing)
+        .map(|window| window[n - 1])
+        .collect()
+}
+
+#[cfg(test)]
+mod banned_ngram_tokens_tests {
+    use super::banned_ngram_tokens;
+
+    #[test]
+    fn bans_the_token_that_would_repeat_a_previously_seen_trigram() {
+        // "1 2" was followed by 3 once already; the sequence now trails
+        // with "1 2" again, so 3 should be banned from repeating it.
+        let tokens = [1, 2, 3, 1, 2];
+        assert_eq!(banned_ngram_tokens(&tokens, 3), vec![3]);
+    }
+
+    #[test]
+    fn n_zero_disables_blocking() {
+        assert_eq!(banned_ngram_tokens(&[1, 2, 3], 0), Vec::<u32>::new());
+    }
+
+    #[test]
+    fn shorter_history_than_n_bans_nothing() {
+        assert_eq!(banned_ngram_tokens(&[1, 2], 3), Vec::<u32>::new());
+    }
+
+    #[test]
+    fn no_matching_ngram_bans_nothing() {
+        let tokens = [1, 2, 3, 4, 5];
+        assert_eq!(banned_ngram_tokens(&tokens, 2), Vec::<u32>::new());
+    }
+}
+
+fn log_softmax(logits: &[f32]) -> Vec<f64> {
+    let max = logits.iter().fold(f32::NEG_INFINITY, |a, &b| a.max(b)) as f64;
+    let sum_exp: f64 = logits.iter().map(|&x| (x as f64 - max).exp()).sum();
+    let log_sum_exp = sum_exp.ln();
+    logits.iter().map(|&x| x as f64 - max - log_sum_exp).collect()
+}
+
 impl ModelConditionalGeneration {
     pub fn load(
         weights: Vec<u8>,
@@ -47,15 +173,167 @@ impl ModelConditionalGeneration {
             config,
         })
     }
-    pub fn decode(&mut self, input: ConditionalGenerationParams) -> Result<ConditionalGenerationOutput, String> {
+
+    /// Beam-search decoding over `num_beams` live hypotheses. Each step
+    /// re-runs every live hypothesis from scratch (clearing the KV cache
+    /// first) since the model only holds a single cache and hypotheses
+    /// diverge, then keeps the best `num_beams` continuations that have
+    /// not produced EOS yet.
+    fn decode_beam(
+        &mut self,
+        encoder_output: &Tensor,
+        max_length: usize,
+        num_beams: usize,
+        length_penalty: f64,
+        early_stopping: bool,
+        no_repeat_ngram_size: usize,
+        min_length: usize,
+    ) -> Result<String, String> {
+        let device = &Device::Cpu;
+        let pad_token_id = self.config.pad_token_id as u32;
+        let eos_token_id = self.config.eos_token_id as u32;
+
+        let mut beams = vec![BeamHypothesis {
+            tokens: vec![pad_token_id],
+            cumulative_logprob: 0.0,
+        }];
+        let mut finished: Vec<BeamHypothesis> = vec![];
+
+        while beams.iter().map(|b| b.tokens.len()).max().unwrap_or(0) <= max_length {
+            if early_stopping && finished.len() >= num_beams {
+                break;
+            }
+            if beams.is_empty() {
+                break;
+            }
+
+            let mut candidates: Vec<BeamHypothesis> = vec![];
+            for beam in &beams {
+                self.model.clear_kv_cache();
+                let tsr = match Tensor::new(beam.tokens.as_slice(), device) {
+                    Ok(tsr) => tsr,
+                    Err(e) => {
+                        return Err(format!("Failed to create tensor: {:?}", e.to_string()));
+                    }
+                };
+                let decoder_token_ids = match tsr.unsqueeze(0) {
+                    Ok(tsr) => tsr,
+                    Err(e) => {
+                        return Err(format!("Failed to unsqueeze tensor: {:?}", e.to_string()));
+                    }
+                };
+                let logits = match self.model.decode(&decoder_token_ids, encoder_output) {
+                    Ok(tsr) => tsr,
+                    Err(e) => {
+                        return Err(format!("Failed to decode tensor: {:?}", e.to_string()));
+                    }
+                };
+                let logits = match logits.squeeze(0) {
+                    Ok(tsr) => tsr,
+                    Err(e) => {
+                        return Err(format!("Failed to squeeze tensor: {:?}", e.to_string()));
+                    }
+                };
+                let logits = match logits.to_vec1::<f32>() {
+                    Ok(v) => v,
+                    Err(e) => {
+                        return Err(format!("Failed to read logits: {:?}", e.to_string()));
+                    }
+                };
+                let mut logprobs = log_softmax(&logits);
+                if no_repeat_ngram_size > 0 {
+                    for token_id in banned_ngram_tokens(&beam.tokens, no_repeat_ngram_size) {
+                        logprobs[token_id as usize] = f64::NEG_INFINITY;
+                    }
+                }
+                if beam.tokens.len() - 1 < min_length {
+                    logprobs[eos_token_id as usize] = f64::NEG_INFINITY;
+                }
+                let mut top: Vec<(usize, f64)> = logprobs.iter().copied().enumerate().collect();
+                top.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap());
+                top.truncate(2 * num_beams);
+
+                for (token_id, logprob) in top {
+                    let mut tokens = beam.tokens.clone();
+                    tokens.push(token_id as u32);
+                    candidates.push(BeamHypothesis {
+                        tokens,
+                        cumulative_logprob: beam.cumulative_logprob + logprob,
+                    });
+                }
+            }
+
+            candidates.sort_by(|a, b| {
+                b.cumulative_logprob
+                    .partial_cmp(&a.cumulative_logprob)
+                    .unwrap()
+            });
+
+            beams = vec![];
+            for candidate in candidates {
+                if beams.len() >= num_beams {
+                    break;
+                }
+                if *candidate.tokens.last().unwrap() == eos_token_id {
+                    let len = (candidate.tokens.len() - 1) as f64;
+                    finished.push(BeamHypothesis {
+                        tokens: candidate.tokens,
+                        cumulative_logprob: candidate.cumulative_logprob / len.powf(length_penalty),
+                    });
+                } else {
+                    beams.push(candidate);
+                }
+            }
+        }
+
+        if finished.is_empty() {
+            finished.extend(beams.into_iter().map(|b| {
+                let len = (b.tokens.len() - 1).max(1) as f64;
+                BeamHypothesis {
+                    cumulative_logprob: b.cumulative_logprob / len.powf(length_penalty),
+                    tokens: b.tokens,
+                }
+            }));
+        }
+
+        let best = finished
+            .into_iter()
+            .max_by(|a, b| a.cumulative_logprob.partial_cmp(&b.cumulative_logprob).unwrap())
+            .ok_or_else(|| "Beam search produced no hypotheses".to_string())?;
+
+        self.tokenizer
+            .decode(&best.tokens[1..], true)
+            .map_err(|m| m.to_string())
+    }
+
+    pub fn decode(
+        &mut self,
+        prompt: &str,
+        input: ConditionalGenerationParams,
+    ) -> Result<ConditionalGenerationOutput, String> {
         let device = &Device::Cpu;
         self.model.clear_kv_cache();
         let mut output_token_ids = [self.config.pad_token_id as u32].to_vec();
-        let prompt = input.prompt;
         let repeat_penalty = input.repeat_penalty;
         let repeat_last_n = input.repeat_last_n;
         let seed = input.seed;
-        let max_length = usize::clamp(input.max_length.unwrap_or(512), 0, 512);
+        // T5's relative-position attention has no intrinsic max sequence
+        // length, so there's no config field that directly names one.
+        // `relative_attention_max_distance` is the farthest distance the
+        // model's position biases were tuned to bucket; position-bias
+        // quality degrades gradually past it rather than breaking outright,
+        // so a generous multiple of it makes a practical generation ceiling
+        // that still scales with how this particular model was configured,
+        // instead of a ceiling disconnected from `self.config` entirely.
+        // `max_length: None` means "generate until EOS up to that ceiling".
+        const MAX_GENERATION_LENGTH_FACTOR: usize = 8;
+        let model_max_length =
+            self.config.relative_attention_max_distance * MAX_GENERATION_LENGTH_FACTOR;
+        let max_length = match input.max_length {
+            Some(len) => len.min(model_max_length),
+            None => model_max_length,
+        };
+        let min_length = input.min_length;
         let temperature = if input.temperature <= 0. {
             None
         } else {
@@ -94,7 +372,22 @@ impl ModelConditionalGeneration {
             }
 
         };
+
+        if input.num_beams > 1 {
+            let generation = self.decode_beam(
+                &encoder_output,
+                max_length,
+                input.num_beams,
+                input.length_penalty,
+                input.early_stopping,
+                input.no_repeat_ngram_size,
+                min_length,
+            )?;
+            return Ok(ConditionalGenerationOutput { generation });
+        }
+
         let mut decoded = String::new();
+        let mut token_stream = TokenOutputStream::new();
         for index in 0.. {
             if output_token_ids.len() > max_length {
                 break;
@@ -144,8 +437,50 @@ impl ModelConditionalGeneration {
                     &output_token_ids[start_at..],
                 )
             };
+            let logits = logits.unwrap();
+            let logits = if input.no_repeat_ngram_size == 0 {
+                logits
+            } else {
+                let banned = banned_ngram_tokens(&output_token_ids, input.no_repeat_ngram_size);
+                if banned.is_empty() {
+                    logits
+                } else {
+                    let mut values = match logits.to_vec1::<f32>() {
+                        Ok(v) => v,
+                        Err(e) => {
+                            return Err(format!("Failed to read logits: {:?}", e.to_string()));
+                        }
+                    };
+                    for token_id in banned {
+                        values[token_id as usize] = f32::NEG_INFINITY;
+                    }
+                    match Tensor::new(values.as_slice(), device) {
+                        Ok(tsr) => tsr,
+                        Err(e) => {
+                            return Err(format!("Failed to create tensor: {:?}", e.to_string()));
+                        }
+                    }
+                }
+            };
+            let logits = if output_token_ids.len() - 1 < min_length {
+                let mut values = match logits.to_vec1::<f32>() {
+                    Ok(v) => v,
+                    Err(e) => {
+                        return Err(format!("Failed to read logits: {:?}", e.to_string()));
+                    }
+                };
+                values[self.config.eos_token_id] = f32::NEG_INFINITY;
+                match Tensor::new(values.as_slice(), device) {
+                    Ok(tsr) => tsr,
+                    Err(e) => {
+                        return Err(format!("Failed to create tensor: {:?}", e.to_string()));
+                    }
+                }
+            } else {
+                logits
+            };
 
-            let next_token_id = match logits_processor.sample(&logits.unwrap()) {
+            let next_token_id = match logits_processor.sample(&logits) {
                 Ok(tsr) => tsr,
                 Err(e) => {
                     return Err(format!("Failed to sample tensor: {:?}", e.to_string()));
@@ -155,8 +490,7 @@ impl ModelConditionalGeneration {
                 break;
             }
             output_token_ids.push(next_token_id);
-            if let Some(text) = self.tokenizer.id_to_token(next_token_id) {
-                let text = text.replace('▁', " ").replace("<0x0A>", "\n");
+            if let Some(text) = token_stream.next_token(&self.tokenizer, next_token_id)? {
                 decoded += &text;
             }
         }
@@ -168,16 +502,31 @@ impl ModelConditionalGeneration {
     }
 }
 
+impl TextTransform for ModelConditionalGeneration {
+    fn load(weights: Vec<u8>, tokenizer: Vec<u8>, config: Vec<u8>) -> Result<Self, String> {
+        ModelConditionalGeneration::load(weights, tokenizer, config)
+    }
+
+    fn run(&mut self, prompt: &str, params: ConditionalGenerationParams) -> Result<String, String> {
+        self.decode(prompt, params).map(|output| output.generation)
+    }
+}
+
 pub struct ConditionalGenerationOutput {
     pub generation: String,
 }
 
+#[derive(Clone)]
 pub struct ConditionalGenerationParams {
-    pub prompt: String,
     pub temperature: f64,
     pub seed: u64,
     pub top_p: f64,
     pub repeat_penalty: f32,
     pub repeat_last_n: usize,
     pub max_length: Option<usize>,
+    pub min_length: usize,
+    pub num_beams: usize,
+    pub length_penalty: f64,
+    pub early_stopping: bool,
+    pub no_repeat_ngram_size: usize,
 }
\ No newline at end of file